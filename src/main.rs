@@ -3,14 +3,17 @@ use clipboard::{ClipboardContext, ClipboardProvider};
 use colored::Colorize;
 use dialoguer::{
     theme::{ColorfulTheme, Theme},
-    Input, MultiSelect, Password,
+    Confirm, Input, MultiSelect, Password,
 };
 use enum_iterator::IntoEnumIterator;
 use hyper::service::{make_service_fn, service_fn};
 use keyring::Keyring;
+use rand::Rng;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 use tokio::sync::broadcast;
 
@@ -26,6 +29,53 @@ pub struct Opts {
     /// Alias for name of GitHub app to store on keychain (defaults to "default")
     #[structopt(long, short)]
     alias: Option<String>,
+    /// Use GitHub's device authorization flow instead of a local redirect
+    /// server, for headless use on servers, containers, and over SSH
+    #[structopt(long)]
+    device: bool,
+    /// The host to authorize and dispense tokens against (defaults to
+    /// "github.com"), for use with GitHub Enterprise Server
+    #[structopt(long)]
+    host: Option<String>,
+    /// Where to send the minted token: "clipboard" (default), "stdout", or "json"
+    #[structopt(long, default_value = "clipboard")]
+    output: Output,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Mint a fresh access token from a previously stored refresh token,
+    /// without re-running the browser or device flow
+    Refresh,
+}
+
+/// Where to send a minted token
+#[derive(Clone, Copy)]
+enum Output {
+    /// Copy the token to the system clipboard (default)
+    Clipboard,
+    /// Print the raw token to stdout, for piping into another tool
+    Stdout,
+    /// Emit `{ "access_token", "scopes", "expires_at" }` as JSON
+    Json,
+}
+
+impl std::str::FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "clipboard" => Ok(Output::Clipboard),
+            "stdout" => Ok(Output::Stdout),
+            "json" => Ok(Output::Json),
+            other => Err(anyhow::anyhow!(
+                "unknown output `{}`, expected one of clipboard, stdout, json",
+                other
+            )),
+        }
+    }
 }
 
 include!(concat!(env!("OUT_DIR"), "/scope.rs"));
@@ -69,12 +119,76 @@ impl Scope {
 #[derive(Deserialize)]
 struct AccessTokenResponse {
     access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+    refresh_token_expires_in: Option<u64>,
+}
+
+/// A minted token's lifecycle, persisted in the keyring next to the `App`
+/// credentials so a later `refresh` can mint a new token without a browser
+#[derive(Clone, Serialize, Deserialize)]
+struct TokenRecord {
+    access_token: String,
+    refresh_token: Option<String>,
+    /// Unix timestamp the access token expires at, absent for non-expiring tokens
+    expires_at: Option<u64>,
+    /// Unix timestamp the refresh token itself expires at
+    refresh_token_expires_at: Option<u64>,
+    /// The scopes that were requested when this token was minted
+    scopes: Vec<Scope>,
+}
+
+impl TokenRecord {
+    fn from_response(
+        response: &AccessTokenResponse,
+        scopes: Vec<Scope>,
+        now: u64,
+    ) -> Self {
+        TokenRecord {
+            access_token: response.access_token.clone(),
+            refresh_token: response.refresh_token.clone(),
+            expires_at: response.expires_in.map(|secs| now + secs),
+            refresh_token_expires_at: response.refresh_token_expires_in.map(|secs| now + secs),
+            scopes,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now() >= expires_at,
+            None => false,
+        }
+    }
+
+    fn load(alias: impl AsRef<str>) -> Option<TokenRecord> {
+        Keyring::new("octopat-token", alias.as_ref())
+            .get_password()
+            .ok()
+            .and_then(|value| serde_json::from_str(&value).ok())
+    }
+
+    fn store(
+        &self,
+        alias: impl AsRef<str>,
+    ) -> anyhow::Result<()> {
+        Keyring::new("octopat-token", alias.as_ref())
+            .set_password(&serde_json::to_string(self)?)
+            .map_err(|e| anyhow::anyhow!(e.to_string()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs()
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 struct App {
     client_id: String,
-    client_secret: String,
+    /// Absent for public apps, which authenticate with PKCE alone
+    client_secret: Option<String>,
 }
 
 impl App {
@@ -96,11 +210,16 @@ impl App {
                     .with_prompt("Your client id")
                     .interact()?;
                 let client_secret = Password::with_theme(theme)
-                    .with_prompt("Your client secret")
+                    .with_prompt("Your client secret (leave blank for a public app using PKCE)")
+                    .allow_empty_password(true)
                     .interact()?;
                 let app = App {
                     client_id,
-                    client_secret,
+                    client_secret: if client_secret.is_empty() {
+                        None
+                    } else {
+                        Some(client_secret)
+                    },
                 };
                 keyring
                     .set_password(&serde_json::to_string(&app)?)
@@ -112,41 +231,249 @@ impl App {
     }
 }
 
+/// A PKCE code verifier, kept in memory for the lifetime of a single
+/// authorization attempt and never persisted to the keyring
+#[derive(Clone)]
+struct CodeVerifier(String);
+
+impl CodeVerifier {
+    /// Generates a random verifier from the unreserved character set,
+    /// per https://tools.ietf.org/html/rfc7636#section-4.1
+    fn generate() -> Self {
+        const UNRESERVED: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+        let mut rng = rand::thread_rng();
+        let verifier = (0..128)
+            .map(|_| UNRESERVED[rng.gen_range(0..UNRESERVED.len())] as char)
+            .collect();
+        CodeVerifier(verifier)
+    }
+
+    /// `code_challenge = BASE64URL-NOPAD(SHA256(code_verifier))`
+    fn challenge(&self) -> String {
+        base64::encode_config(Sha256::digest(self.0.as_bytes()), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// The base URL for OAuth endpoints on a given host, e.g.
+/// `https://github.com/login/oauth` or `https://github.example.com/login/oauth`
+fn oauth_base(host: impl AsRef<str>) -> String {
+    format!("https://{}/login/oauth", host.as_ref())
+}
+
+/// The base URL for REST API calls on a given host. GitHub Enterprise
+/// Server serves its API under `/api/v3` rather than a dedicated subdomain
+fn api_base(host: impl AsRef<str>) -> String {
+    let host = host.as_ref();
+    if host == "github.com" {
+        "https://api.github.com".to_string()
+    } else {
+        format!("https://{}/api/v3", host)
+    }
+}
+
 fn authorization_url(
+    host: impl AsRef<str>,
     client_id: impl AsRef<str>,
     scopes: Vec<Scope>,
     port: u16,
+    code_challenge: impl AsRef<str>,
 ) -> String {
     format!(
-        "https://github.com/login/oauth/authorize?client_id={client_id}&redirect_uri=http://localhost:{port}/&scope={scope}",
+        "{oauth_base}/authorize?client_id={client_id}&redirect_uri=http://localhost:{port}/&scope={scope}&code_challenge={code_challenge}&code_challenge_method=S256",
+        oauth_base = oauth_base(host),
         client_id = client_id.as_ref(),
         scope = scopes.into_iter().map(|s| s.to_string()).collect::<Vec<_>>().join("%20"),
-        port = port
+        port = port,
+        code_challenge = code_challenge.as_ref()
     )
 }
 
 async fn exchange_token(
+    host: impl AsRef<str>,
     app: &App,
     code: impl AsRef<str>,
+    code_verifier: &CodeVerifier,
+) -> Result<AccessTokenResponse, reqwest::Error> {
+    let App {
+        client_id,
+        client_secret,
+    } = app;
+    let mut form = vec![
+        ("client_id", client_id.as_str()),
+        ("code", code.as_ref()),
+        ("code_verifier", code_verifier.0.as_str()),
+    ];
+    if let Some(client_secret) = client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+    Ok(Client::new()
+        .post(format!("{}/access_token", oauth_base(host)))
+        .header("Accept", "application/json")
+        .form(&form)
+        .send()
+        .await?
+        .json()
+        .await?)
+}
+
+async fn refresh_token(
+    host: impl AsRef<str>,
+    app: &App,
+    refresh_token: impl AsRef<str>,
 ) -> Result<AccessTokenResponse, reqwest::Error> {
     let App {
         client_id,
         client_secret,
     } = app;
+    let mut form = vec![
+        ("client_id", client_id.as_str()),
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_ref()),
+    ];
+    if let Some(client_secret) = client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
     Ok(Client::new()
-        .post("https://github.com/login/oauth/access_token")
+        .post(format!("{}/access_token", oauth_base(host)))
         .header("Accept", "application/json")
-        .form(&[
-            ("client_id", client_id.as_ref()),
-            ("client_secret", client_secret.as_ref()),
-            ("code", code.as_ref()),
-        ])
+        .form(&form)
         .send()
         .await?
         .json()
         .await?)
 }
 
+#[derive(Serialize)]
+struct TokenOutput<'a> {
+    access_token: &'a str,
+    scopes: Vec<String>,
+    expires_at: Option<u64>,
+}
+
+/// Sends a minted token to the sink the user asked for, suppressing the
+/// clipboard confirmation message when output isn't `Output::Clipboard`
+fn deliver(
+    output: Output,
+    access_token: impl AsRef<str>,
+    scopes: &[Scope],
+    expires_at: Option<u64>,
+) -> anyhow::Result<()> {
+    let access_token = access_token.as_ref();
+    match output {
+        Output::Clipboard => {
+            let mut clip = ClipboardContext::new().expect("failed to get access to clipboard");
+            clip.set_contents(access_token.to_string())
+                .expect("failed to set clipboard contents");
+            println!("✨{}", "Token copied to clipboard".bold());
+        }
+        Output::Stdout => println!("{}", access_token),
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string(&TokenOutput {
+                access_token,
+                scopes: scopes.iter().map(|s| s.to_string()).collect(),
+                expires_at,
+            })?
+        ),
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct User {
+    login: String,
+    name: Option<String>,
+}
+
+/// Confirms a freshly minted token actually authenticates and reports the
+/// scopes GitHub granted, warning about any requested scope that's missing
+async fn verify_token(
+    host: impl AsRef<str>,
+    access_token: impl AsRef<str>,
+    requested: &[Scope],
+) -> anyhow::Result<()> {
+    let res = Client::new()
+        .get(format!("{}/user", api_base(host)))
+        .header("Authorization", format!("token {}", access_token.as_ref()))
+        .header("User-Agent", "octopat")
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        anyhow::bail!(
+            "token verification failed with status {}; double check the app's client id/secret and requested scopes",
+            res.status()
+        );
+    }
+    let granted: Option<Vec<String>> = res
+        .headers()
+        .get("X-OAuth-Scopes")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .map(|scope| scope.trim().to_string())
+                .filter(|scope| !scope.is_empty())
+                .collect()
+        });
+    let User { login, name } = res.json().await?;
+    println!(
+        "👤 Authenticated as {}",
+        name.unwrap_or(login).bold()
+    );
+    if let Some(granted) = granted {
+        println!("🔓 Granted scopes: {}", granted.join(", "));
+        for scope in requested {
+            let scope = scope.to_string();
+            if !granted.contains(&scope) {
+                println!(
+                    "{} requested scope `{}` was not granted",
+                    "⚠".yellow(),
+                    scope
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `verify_token` as a best-effort diagnostic: a failure (rate limit,
+/// transient network blip, etc.) is printed as a warning rather than
+/// discarding an otherwise successfully minted token
+async fn warn_on_verify_failure(
+    host: impl AsRef<str>,
+    access_token: impl AsRef<str>,
+    requested: &[Scope],
+) {
+    if let Err(e) = verify_token(host, access_token, requested).await {
+        println!("{} could not verify the minted token: {}", "⚠".yellow(), e);
+    }
+}
+
+/// Offers to reuse a still-valid stored token instead of starting a new
+/// authorization flow. Returns `true` if a token was reused and delivered
+fn try_reuse(
+    alias: impl AsRef<str>,
+    output: Output,
+    theme: &dyn Theme,
+) -> anyhow::Result<bool> {
+    match TokenRecord::load(alias) {
+        Some(token) if !token.is_expired() => {
+            if Confirm::with_theme(theme)
+                .with_prompt("A valid token for this alias already exists. Reuse it?")
+                .default(true)
+                .interact()?
+            {
+                deliver(output, token.access_token, &token.scopes, token.expires_at)?;
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
 fn html(content: impl Into<String>) -> anyhow::Result<hyper::Response<hyper::Body>> {
     Ok(hyper::Response::builder()
         .header("Content-Type", "text/html")
@@ -154,14 +481,26 @@ fn html(content: impl Into<String>) -> anyhow::Result<hyper::Response<hyper::Bod
 }
 
 async fn create(
+    host: String,
     port: u16,
     alias: String,
+    output: Output,
     theme: &dyn Theme,
 ) -> anyhow::Result<()> {
-    let app = App::prompt(theme, alias)?;
+    if try_reuse(&alias, output, theme)? {
+        return Ok(());
+    }
+    let app = App::prompt(theme, alias.clone())?;
     let scopes = Scope::prompt(theme)?;
+    let code_verifier = CodeVerifier::generate();
     println!("🧭 Navigating to GitHub for authorization");
-    opener::open(authorization_url(app.client_id.as_str(), scopes, port))?;
+    opener::open(authorization_url(
+        host.clone(),
+        app.client_id.as_str(),
+        scopes.clone(),
+        port,
+        code_verifier.challenge(),
+    ))?;
 
     let (tx, mut rx) = broadcast::channel(1);
     // spin up a tiny http service to handle local redirection
@@ -170,10 +509,18 @@ async fn create(
         hyper::Server::bind(&([127, 0, 0, 1], port).into()).serve(make_service_fn(move |_| {
             let app = app.clone();
             let tx = tx.clone();
+            let code_verifier = code_verifier.clone();
+            let scopes = scopes.clone();
+            let alias = alias.clone();
+            let host = host.clone();
             async {
                 Ok::<_, anyhow::Error>(service_fn(move |req| {
                     let app = app.clone();
                     let tx = tx.clone();
+                    let code_verifier = code_verifier.clone();
+                    let scopes = scopes.clone();
+                    let alias = alias.clone();
+                    let host = host.clone();
                     async move {
                         match req.uri().path() {
                             // because browsers always request this
@@ -182,14 +529,31 @@ async fn create(
                                 println!("👍 Received response. You can close the browser tab now");
                                 match req.query_param("code") {
                                     Some(code) => {
-                                        let AccessTokenResponse { access_token } =
-                                            exchange_token(&app, code).await?;
-                                        let mut clip = ClipboardContext::new()
-                                            .expect("failed to get access to clipboard");
-                                        clip.set_contents(access_token)
-                                            .expect("failed to set clipboard contents");
-
-                                        println!("✨{}", "Token copied to clipboard".bold());
+                                        let response = exchange_token(
+                                            host.clone(),
+                                            &app,
+                                            code,
+                                            &code_verifier,
+                                        )
+                                        .await?;
+                                        let record = TokenRecord::from_response(
+                                            &response,
+                                            scopes.clone(),
+                                            now(),
+                                        );
+                                        record.store(&alias)?;
+                                        deliver(
+                                            output,
+                                            &record.access_token,
+                                            &record.scopes,
+                                            record.expires_at,
+                                        )?;
+                                        warn_on_verify_failure(
+                                            host,
+                                            &record.access_token,
+                                            &scopes,
+                                        )
+                                        .await;
                                         tx.send(()).unwrap(); // tokio error doesn't impl std error?
                                         Ok::<_, anyhow::Error>(html(
                                             include_str!("../pages/success.html")
@@ -221,15 +585,145 @@ async fn create(
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeviceTokenResponse {
+    Token(AccessTokenResponse),
+    Error { error: String },
+}
+
+/// Authorizes via GitHub's device flow, for use where no browser is
+/// available to bind a local redirect server to, e.g. over SSH
+async fn create_with_device_flow(
+    host: String,
+    alias: String,
+    output: Output,
+    theme: &dyn Theme,
+) -> anyhow::Result<()> {
+    if try_reuse(&alias, output, theme)? {
+        return Ok(());
+    }
+    let app = App::prompt(theme, alias.clone())?;
+    let scopes = Scope::prompt(theme)?;
+    let scope = scopes
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let client = Client::new();
+    let DeviceCodeResponse {
+        device_code,
+        user_code,
+        verification_uri,
+        expires_in,
+        mut interval,
+    } = client
+        .post(format!("https://{}/login/device/code", host))
+        .header("Accept", "application/json")
+        .form(&[("client_id", app.client_id.as_str()), ("scope", scope.as_str())])
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    println!(
+        "🔑 Enter code {} at {}",
+        user_code.bold(),
+        verification_uri
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(expires_in);
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        if Instant::now() >= deadline {
+            anyhow::bail!("device code expired before authorization was completed");
+        }
+        match client
+            .post(format!("{}/access_token", oauth_base(&host)))
+            .header("Accept", "application/json")
+            .form(&[
+                ("client_id", app.client_id.as_str()),
+                ("device_code", device_code.as_str()),
+                (
+                    "grant_type",
+                    "urn:ietf:params:oauth:grant-type:device_code",
+                ),
+            ])
+            .send()
+            .await?
+            .json()
+            .await?
+        {
+            DeviceTokenResponse::Token(response) => {
+                let record = TokenRecord::from_response(&response, scopes.clone(), now());
+                record.store(&alias)?;
+                deliver(output, &record.access_token, &record.scopes, record.expires_at)?;
+                warn_on_verify_failure(&host, &record.access_token, &scopes).await;
+                return Ok(());
+            }
+            DeviceTokenResponse::Error { error } => match error.as_str() {
+                "authorization_pending" => continue,
+                "slow_down" => {
+                    interval += 5;
+                    continue;
+                }
+                other => anyhow::bail!("device authorization failed: {}", other),
+            },
+        }
+    }
+}
+
+/// Mints a fresh access token from a stored refresh token, without
+/// re-running the browser or device flow
+async fn refresh(
+    host: String,
+    alias: String,
+    output: Output,
+    theme: &dyn Theme,
+) -> anyhow::Result<()> {
+    let token = TokenRecord::load(&alias)
+        .ok_or_else(|| anyhow::anyhow!("no stored token found for alias `{}`", alias))?;
+    let refresh = token
+        .refresh_token
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("stored token for alias `{}` has no refresh token", alias))?;
+    let app = App::prompt(theme, alias.clone())?;
+    let response = refresh_token(host.clone(), &app, refresh).await?;
+    let record = TokenRecord::from_response(&response, token.scopes, now());
+    record.store(&alias)?;
+    deliver(output, &record.access_token, &record.scopes, record.expires_at)?;
+    warn_on_verify_failure(host, &record.access_token, &record.scopes).await;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let Opts { port, alias } = Opts::from_args();
-    create(
-        port.unwrap_or(4567),
-        alias.unwrap_or_else(|| "default".into()),
-        &ColorfulTheme::default(),
-    )
-    .await?;
+    let Opts {
+        port,
+        alias,
+        device,
+        host,
+        output,
+        command,
+    } = Opts::from_args();
+    let alias = alias.unwrap_or_else(|| "default".into());
+    let host = host.unwrap_or_else(|| "github.com".into());
+    let theme = ColorfulTheme::default();
+    match command {
+        Some(Command::Refresh) => refresh(host, alias, output, &theme).await?,
+        None if device => create_with_device_flow(host, alias, output, &theme).await?,
+        None => create(host, port.unwrap_or(4567), alias, output, &theme).await?,
+    }
 
     Ok(())
 }
@@ -241,11 +735,50 @@ mod tests {
     #[test]
     fn auth_url_returns_expected_url() {
         assert_eq!(
-            authorization_url("client_id", vec![Scope::AdminOrg, Scope::AdminRepoHook], 4567),
-            "https://github.com/login/oauth/authorize?client_id=client_id&redirect_uri=http://localhost:4567/&scope=admin:org%20admin:repo_hook"
+            authorization_url(
+                "github.com",
+                "client_id",
+                vec![Scope::AdminOrg, Scope::AdminRepoHook],
+                4567,
+                "challenge"
+            ),
+            "https://github.com/login/oauth/authorize?client_id=client_id&redirect_uri=http://localhost:4567/&scope=admin:org%20admin:repo_hook&code_challenge=challenge&code_challenge_method=S256"
+        )
+    }
+
+    #[test]
+    fn auth_url_supports_enterprise_hosts() {
+        assert_eq!(
+            authorization_url(
+                "github.example.com",
+                "client_id",
+                vec![Scope::Repo],
+                4567,
+                "challenge"
+            ),
+            "https://github.example.com/login/oauth/authorize?client_id=client_id&redirect_uri=http://localhost:4567/&scope=repo&code_challenge=challenge&code_challenge_method=S256"
         )
     }
 
+    #[test]
+    fn api_base_uses_api_v3_for_enterprise_hosts() {
+        assert_eq!(api_base("github.com"), "https://api.github.com");
+        assert_eq!(
+            api_base("github.example.com"),
+            "https://github.example.com/api/v3"
+        );
+    }
+
+    #[test]
+    fn code_verifier_challenge_is_base64url_nopad_sha256() {
+        let verifier = CodeVerifier("a".repeat(64));
+        let challenge = verifier.challenge();
+        assert_eq!(challenge, "_-BU_nrgy23GXDr5th1SCfQ5hR20PQulmXM33xVGaOs");
+        assert!(!challenge.contains('+'));
+        assert!(!challenge.contains('/'));
+        assert!(!challenge.contains('='));
+    }
+
     #[test]
     fn scope_deserializes_into_identifier() -> Result<(), Box<dyn std::error::Error>> {
         assert_eq!(